@@ -1,107 +1,453 @@
+mod storage;
+
 use anyhow::{Context, Result};
 use base64::{engine::general_purpose, Engine as _};
-use csv::Writer;
+use clap::Parser;
 use futures::stream::{self, StreamExt};
+use image::AnimationDecoder;
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::Rng;
+use reqwest::{StatusCode, Url};
 use serde_json::json;
-use std::fs::{File, OpenOptions};
-use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use walkdir::WalkDir;
+use std::time::{Duration, Instant};
+use storage::{ImageRef, ResultSink};
+use tokio::sync::{Mutex, Semaphore};
+
+/// Number of attempts (including the first) made against the API before
+/// giving up on an image.
+const MAX_ATTEMPTS: u32 = 5;
+/// Base delay for exponential backoff between retries.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Batch-sends images to a vision API and records the responses to a CSV.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// Directory to recursively scan for images, or an `s3://bucket/prefix` URI
+    #[arg(long, default_value = "IMAGES")]
+    input_dir: String,
+
+    /// CSV file to append results to (also read on startup to skip
+    /// already-processed images); a local path or an `s3://bucket/key` URI
+    #[arg(long, default_value = "api_responses.csv")]
+    output_csv: String,
+
+    /// Base URL of the image API endpoint
+    #[arg(long)]
+    api_url: Url,
+
+    /// Maximum number of in-flight API requests
+    #[arg(long, default_value_t = 10)]
+    concurrency: usize,
+
+    /// Comma-separated list of file extensions (without the dot) to treat as images
+    #[arg(long, value_delimiter = ',', default_value = "jpg,jpeg,png,gif,bmp")]
+    extensions: Vec<String>,
+
+    /// How each image is packaged in the API request
+    #[arg(long, value_enum, default_value_t = UploadMode::JsonBase64)]
+    upload_mode: UploadMode,
+
+    /// Resize images so their longest edge is at most this many pixels
+    /// before upload, preserving aspect ratio (the file on disk is left
+    /// untouched). Disabled unless set.
+    #[arg(long)]
+    max_dim: Option<u32>,
+
+    /// JPEG quality (1-100) used when re-encoding a resized image
+    #[arg(long, default_value_t = 85, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: u8,
+}
+
+/// Format used to package an image for the API request.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum UploadMode {
+    /// Base64-encode the image into a JSON body (the original behavior)
+    JsonBase64,
+    /// Stream the raw image bytes as a `multipart/form-data` part
+    Multipart,
+}
+
+/// Settings shared by every `process_image` call for a given run.
+struct RunConfig {
+    api_url: Url,
+    upload_mode: UploadMode,
+    max_dim: Option<u32>,
+    quality: u8,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let images_folder = Path::new("IMAGES");
-    let csv_path = Path::new("api_responses.csv");
-    let csv_writer = Arc::new(Mutex::new(create_csv_writer(csv_path)?));
+    let args = Args::parse();
+
+    let (already_processed, csv_writer) = storage::open_output(&args.output_csv).await?;
+    let csv_writer = Arc::new(Mutex::new(csv_writer));
+    let semaphore = Arc::new(Semaphore::new(args.concurrency));
 
-    let image_paths: Vec<PathBuf> = WalkDir::new(images_folder)
+    let images: Vec<ImageRef> = storage::list_images(&args.input_dir, &args.extensions)
+        .await?
         .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.path().is_file() && is_image(e.path()))
-        .map(|e| e.path().to_owned())
+        .filter(|image| !already_processed.contains(&image.display()))
         .collect();
 
-    println!("Found {} images", image_paths.len());
+    println!(
+        "Found {} images ({} already processed, skipping)",
+        images.len(),
+        already_processed.len()
+    );
 
-    let tasks = stream::iter(image_paths)
-        .map(|path| {
+    let progress = ProgressBar::new(images.len() as u64);
+    progress.set_style(
+        ProgressStyle::with_template(
+            "{elapsed_precise} {bar:40.cyan/blue} {pos}/{len} {msg} {per_sec} eta={eta}",
+        )
+        .unwrap(),
+    );
+    let success_count = Arc::new(AtomicU64::new(0));
+    let error_count = Arc::new(AtomicU64::new(0));
+    let latencies = Arc::new(Mutex::new(Vec::<Duration>::new()));
+
+    let run_config = Arc::new(RunConfig {
+        api_url: args.api_url,
+        upload_mode: args.upload_mode,
+        max_dim: args.max_dim,
+        quality: args.quality,
+    });
+    let tasks = stream::iter(images)
+        .map(|image| {
             let csv_writer = Arc::clone(&csv_writer);
-            let path_clone = path.clone();
+            let semaphore = Arc::clone(&semaphore);
+            let run_config = Arc::clone(&run_config);
+            let display = image.display();
+            let progress = progress.clone();
+            let success_count = Arc::clone(&success_count);
+            let error_count = Arc::clone(&error_count);
+            let latencies = Arc::clone(&latencies);
             tokio::spawn(async move {
-                match process_image(path_clone, csv_writer).await {
-                    Ok(_) => println!("Successfully processed {:?}", path),
-                    Err(e) => eprintln!("Error processing {:?}: {}", path, e),
+                let result = process_image(
+                    image,
+                    csv_writer,
+                    semaphore,
+                    &run_config,
+                    progress.clone(),
+                )
+                .await;
+
+                match result {
+                    Ok(latency) => {
+                        success_count.fetch_add(1, Ordering::Relaxed);
+                        latencies.lock().await.push(latency);
+                    }
+                    Err(e) => {
+                        error_count.fetch_add(1, Ordering::Relaxed);
+                        progress.println(format!("Error processing {}: {}", display, e));
+                    }
                 }
+                progress.set_message(format!(
+                    "ok={} err={}",
+                    success_count.load(Ordering::Relaxed),
+                    error_count.load(Ordering::Relaxed)
+                ));
+                progress.inc(1);
             })
         })
-        .buffer_unordered(10); // Process up to 10 images concurrently
+        // The semaphore is now what actually bounds in-flight API requests, so
+        // this only needs to be large enough not to throttle task spawning.
+        .buffer_unordered(args.concurrency * 4);
 
     tasks.for_each(|_| async {}).await;
+    progress.finish_and_clear();
+
+    csv_writer.lock().await.flush().await?;
+
+    let latencies = Arc::try_unwrap(latencies)
+        .expect("all spawned tasks finished before this point")
+        .into_inner();
+    print_summary(
+        success_count.load(Ordering::Relaxed),
+        error_count.load(Ordering::Relaxed),
+        &latencies,
+    );
 
     Ok(())
 }
 
-async fn process_image(path: PathBuf, csv_writer: Arc<Mutex<Writer<File>>>) -> Result<()> {
-    let path_str = path.to_string_lossy().into_owned();
-    let image_base64 = tokio::task::spawn_blocking(move || encode_image(&path)).await??;
-    let response = send_to_api(&image_base64).await?;
+fn print_summary(successes: u64, failures: u64, latencies: &[Duration]) {
+    println!(
+        "Processed {} images: {} ok, {} failed",
+        successes + failures,
+        successes,
+        failures
+    );
+
+    if latencies.is_empty() {
+        return;
+    }
+
+    let mut sorted = latencies.to_vec();
+    sorted.sort();
+
+    let total: Duration = sorted.iter().sum();
+    let mean = total / sorted.len() as u32;
+    let median = sorted[sorted.len() / 2];
+
+    println!(
+        "API latency: mean={:.0}ms median={:.0}ms",
+        mean.as_secs_f64() * 1000.0,
+        median.as_secs_f64() * 1000.0
+    );
+}
+
+async fn process_image(
+    image: ImageRef,
+    csv_writer: Arc<Mutex<ResultSink>>,
+    semaphore: Arc<Semaphore>,
+    config: &RunConfig,
+    progress: ProgressBar,
+) -> Result<Duration> {
+    let display = image.display();
+    let mut file_name = image.file_name();
+    let image_bytes = image.read_bytes().await?;
+    let image_bytes = match config.max_dim {
+        Some(max_dim) => {
+            let quality = config.quality;
+            let (resized, format) = tokio::task::spawn_blocking(move || {
+                prepare_image(&image_bytes, max_dim, quality)
+            })
+            .await??;
+            file_name = with_extension_for_format(&file_name, format);
+            resized
+        }
+        None => image_bytes,
+    };
+
+    let _permit = semaphore
+        .acquire()
+        .await
+        .context("Semaphore was closed unexpectedly")?;
+    let started_at = Instant::now();
+    let response = match config.upload_mode {
+        UploadMode::JsonBase64 => {
+            let image_base64 = general_purpose::STANDARD.encode(&image_bytes);
+            send_to_api_json(&config.api_url, &image_base64, &progress).await?
+        }
+        UploadMode::Multipart => {
+            send_to_api_multipart(&config.api_url, &file_name, image_bytes, &progress).await?
+        }
+    };
+    let latency = started_at.elapsed();
+    drop(_permit);
 
     let mut writer = csv_writer.lock().await;
-    writer.write_record(&[path_str, response.to_string()])?;
-    writer.flush()?;
+    writer
+        .write_record(&[display, response.to_string()])
+        .await?;
 
-    Ok(())
+    Ok(latency)
 }
 
-fn create_csv_writer(path: &Path) -> Result<Writer<File>> {
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(path)
-        .context("Failed to open or create CSV file")?;
+/// Decodes `bytes`, shrinks it so its longest edge is at most `max_dim`
+/// pixels (preserving aspect ratio), and re-encodes it, keeping PNGs as PNG
+/// and everything else as JPEG at `quality`. Leaves the source untouched;
+/// the caller decides whether to use the original or the prepared bytes.
+/// Returns the re-encoded bytes along with the format they were written in.
+/// Multi-frame GIFs are returned unmodified: `DynamicImage` has no concept
+/// of animation, so decoding and re-encoding one would silently collapse it
+/// to its first frame.
+fn prepare_image(
+    bytes: &[u8],
+    max_dim: u32,
+    quality: u8,
+) -> Result<(Vec<u8>, image::ImageFormat)> {
+    let format = image::guess_format(bytes).unwrap_or(image::ImageFormat::Jpeg);
+    if format == image::ImageFormat::Gif && is_animated_gif(bytes)? {
+        return Ok((bytes.to_vec(), format));
+    }
 
-    let writer = csv::WriterBuilder::new()
-        .has_headers(false)
-        .from_writer(file);
+    let decoded = image::load_from_memory_with_format(bytes, format)
+        .context("Failed to decode image for resizing")?;
+    let resized = decoded.thumbnail(max_dim, max_dim);
 
-    Ok(writer)
+    let output_format = if format == image::ImageFormat::Png {
+        image::ImageFormat::Png
+    } else {
+        image::ImageFormat::Jpeg
+    };
+
+    let mut output = Vec::new();
+    let mut cursor = std::io::Cursor::new(&mut output);
+    match output_format {
+        image::ImageFormat::Png => {
+            resized
+                .write_to(&mut cursor, image::ImageFormat::Png)
+                .context("Failed to re-encode resized image as PNG")?;
+        }
+        _ => {
+            let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut cursor, quality);
+            resized
+                .write_with_encoder(encoder)
+                .context("Failed to re-encode resized image as JPEG")?;
+        }
+    }
+
+    Ok((output, output_format))
 }
 
-fn is_image(path: &Path) -> bool {
-    let extensions = ["jpg", "jpeg", "png", "gif", "bmp"];
-    path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(|ext| extensions.contains(&ext.to_lowercase().as_str()))
-        .unwrap_or(false)
+/// Whether `bytes` (already known to be a GIF) has more than one frame.
+fn is_animated_gif(bytes: &[u8]) -> Result<bool> {
+    let decoder = image::codecs::gif::GifDecoder::new(std::io::Cursor::new(bytes))
+        .context("Failed to decode GIF header")?;
+    Ok(decoder.into_frames().take(2).count() > 1)
 }
 
-fn encode_image(path: &Path) -> Result<String> {
-    let mut file = File::open(path).context("Failed to open image file")?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)
-        .context("Failed to read image file")?;
-    Ok(general_purpose::STANDARD.encode(buffer))
+/// Swaps a file name's extension to match the format an image was
+/// re-encoded to, so multipart uploads send a correct `Content-Type`.
+fn with_extension_for_format(file_name: &str, format: image::ImageFormat) -> String {
+    let new_extension = match format {
+        image::ImageFormat::Png => "png",
+        image::ImageFormat::Gif => "gif",
+        _ => "jpg",
+    };
+    Path::new(file_name)
+        .with_extension(new_extension)
+        .to_string_lossy()
+        .into_owned()
 }
 
-async fn send_to_api(image_base64: &str) -> Result<serde_json::Value> {
+/// Guesses a `Content-Type` from a file name's extension, falling back to a
+/// generic binary type for anything unrecognized.
+fn mime_type_for(file_name: &str) -> &'static str {
+    match Path::new(file_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase())
+        .as_deref()
+    {
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("bmp") => "image/bmp",
+        _ => "application/octet-stream",
+    }
+}
+
+async fn send_to_api_json(
+    api_url: &Url,
+    image_base64: &str,
+    progress: &ProgressBar,
+) -> Result<serde_json::Value> {
     let client = reqwest::Client::new();
     let payload = json!({
         "image_base64": image_base64
     });
 
-    let response = client
-        .post("http://194..163.14:8995/ping")
-        .json(&payload)
-        .send()
-        .await
-        .context("Failed to send request to API")?;
+    send_with_retry(|| client.post(api_url.clone()).json(&payload), progress).await
+}
 
-    let json_response = response
-        .json()
-        .await
-        .context("Failed to parse API response")?;
-    Ok(json_response)
+async fn send_to_api_multipart(
+    api_url: &Url,
+    file_name: &str,
+    image_bytes: Vec<u8>,
+    progress: &ProgressBar,
+) -> Result<serde_json::Value> {
+    let client = reqwest::Client::new();
+    let file_name = file_name.to_owned();
+    let mime_type = mime_type_for(&file_name);
+
+    send_with_retry(
+        || {
+            let part = reqwest::multipart::Part::bytes(image_bytes.clone())
+                .file_name(file_name.clone())
+                .mime_str(mime_type)
+                .expect("mime_type_for always returns a valid MIME type");
+            let form = reqwest::multipart::Form::new().part("image", part);
+            client.post(api_url.clone()).multipart(form)
+        },
+        progress,
+    )
+    .await
+}
+
+/// Sends a request built by `build_request`, retrying on connection errors
+/// and HTTP 429/5xx responses with exponential backoff, honoring
+/// `Retry-After` when the server sends one. `build_request` is called again
+/// for each attempt since a request body can only be sent once. Retry
+/// warnings go through `progress` rather than `eprintln!` so they don't
+/// corrupt the progress bar's redraw.
+async fn send_with_retry<F>(mut build_request: F, progress: &ProgressBar) -> Result<serde_json::Value>
+where
+    F: FnMut() -> reqwest::RequestBuilder,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        let result = build_request().send().await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                return response
+                    .json()
+                    .await
+                    .context("Failed to parse API response");
+            }
+            Ok(response) if is_retryable_status(response.status()) && attempt < MAX_ATTEMPTS => {
+                let retry_after = retry_after_delay(&response);
+                progress.println(format!(
+                    "API returned {} (attempt {}/{}), retrying",
+                    response.status(),
+                    attempt,
+                    MAX_ATTEMPTS
+                ));
+                tokio::time::sleep(retry_after.unwrap_or_else(|| backoff_delay(attempt))).await;
+            }
+            Ok(response) => {
+                return Err(anyhow::anyhow!(
+                    "API request failed with status {}",
+                    response.status()
+                ));
+            }
+            Err(e) if attempt < MAX_ATTEMPTS && is_retryable_error(&e) => {
+                progress.println(format!(
+                    "Request error on attempt {}/{}: {}, retrying",
+                    attempt, MAX_ATTEMPTS, e
+                ));
+                tokio::time::sleep(backoff_delay(attempt)).await;
+            }
+            Err(e) => return Err(e).context("Failed to send request to API"),
+        }
+    }
+}
+
+/// HTTP 429 and 5xx are treated as transient; everything else is a
+/// permanent failure worth surfacing immediately.
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn is_retryable_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout() || error.is_request()
+}
+
+/// Honors a `Retry-After` header expressed in seconds, if present.
+fn retry_after_delay(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Exponential backoff with full jitter: `INITIAL_BACKOFF * 2^(attempt - 1)`,
+/// randomized in `[0, computed]` to avoid thundering-herd retries.
+fn backoff_delay(attempt: u32) -> Duration {
+    let exp = INITIAL_BACKOFF * 2u32.pow(attempt.saturating_sub(1));
+    let jittered_millis = rand::thread_rng().gen_range(0..=exp.as_millis() as u64);
+    Duration::from_millis(jittered_millis)
 }
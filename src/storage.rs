@@ -0,0 +1,314 @@
+//! Storage backends for image sources and result sinks.
+//!
+//! The tool originally only understood local paths (`WalkDir` for input,
+//! a local CSV file for output). This module generalizes both sides behind
+//! the `object_store` crate so an `s3://bucket/prefix` URI can be used
+//! anywhere a local path used to be, without the rest of the pipeline
+//! caring which backend it's talking to.
+
+use anyhow::{Context, Result};
+use csv::Writer;
+use futures::TryStreamExt;
+use object_store::path::Path as ObjectPath;
+use object_store::{parse_url, ObjectStore};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use url::Url;
+use walkdir::WalkDir;
+
+/// Number of rows buffered before an object-store sink re-uploads the CSV.
+/// Buckets have no append operation, so every upload re-sends the whole
+/// growing file; batching keeps that from happening on every single row
+/// when `--concurrency` is high.
+const OBJECT_STORE_FLUSH_EVERY: usize = 20;
+
+/// A single image from either the local filesystem or an object-store bucket.
+#[derive(Clone)]
+pub enum ImageRef {
+    Local(PathBuf),
+    Object {
+        store: Arc<dyn ObjectStore>,
+        path: ObjectPath,
+        uri: String,
+    },
+}
+
+impl ImageRef {
+    /// Stable identifier used as the CSV key and in log output.
+    pub fn display(&self) -> String {
+        match self {
+            ImageRef::Local(path) => path.to_string_lossy().into_owned(),
+            ImageRef::Object { uri, .. } => uri.clone(),
+        }
+    }
+
+    /// A name suitable for a multipart filename / extension sniffing.
+    pub fn file_name(&self) -> String {
+        match self {
+            ImageRef::Local(path) => path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "image".to_owned()),
+            ImageRef::Object { path, .. } => path
+                .filename()
+                .map(|name| name.to_owned())
+                .unwrap_or_else(|| "image".to_owned()),
+        }
+    }
+
+    pub async fn read_bytes(&self) -> Result<Vec<u8>> {
+        match self {
+            ImageRef::Local(path) => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || read_image_bytes(&path)).await?
+            }
+            ImageRef::Object { store, path, .. } => {
+                let object = store
+                    .get(path)
+                    .await
+                    .context("Failed to fetch object from store")?;
+                let bytes = object
+                    .bytes()
+                    .await
+                    .context("Failed to read object body")?;
+                Ok(bytes.to_vec())
+            }
+        }
+    }
+}
+
+fn read_image_bytes(path: &Path) -> Result<Vec<u8>> {
+    let mut file = File::open(path).context("Failed to open image file")?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)
+        .context("Failed to read image file")?;
+    Ok(buffer)
+}
+
+/// Whether `url` names an object-store bucket (e.g. `s3://...`) rather than
+/// a local `file://` URI or a bare path that happened to parse as a URL.
+fn is_bucket_url(url: &Url) -> bool {
+    url.scheme() != "file" && !url.scheme().is_empty() && url.has_host()
+}
+
+/// Lists images under `input`, a local directory path or an `s3://bucket/prefix` URI.
+pub async fn list_images(input: &str, extensions: &[String]) -> Result<Vec<ImageRef>> {
+    match Url::parse(input) {
+        Ok(url) if is_bucket_url(&url) => list_bucket_images(&url, extensions).await,
+        _ => Ok(list_local_images(Path::new(input), extensions)),
+    }
+}
+
+fn list_local_images(dir: &Path, extensions: &[String]) -> Vec<ImageRef> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file() && has_image_extension(e.path(), extensions))
+        .map(|e| ImageRef::Local(e.path().to_owned()))
+        .collect()
+}
+
+async fn list_bucket_images(url: &Url, extensions: &[String]) -> Result<Vec<ImageRef>> {
+    let (store, prefix) = parse_url(url).context("Failed to parse object store URL")?;
+    let store: Arc<dyn ObjectStore> = Arc::from(store);
+
+    let entries: Vec<_> = store
+        .list(Some(&prefix))
+        .try_collect()
+        .await
+        .context("Failed to list objects in bucket")?;
+
+    Ok(entries
+        .into_iter()
+        .filter(|meta| has_extension(meta.location.extension(), extensions))
+        .map(|meta| ImageRef::Object {
+            store: Arc::clone(&store),
+            uri: format!("{}://{}/{}", url.scheme(), url_authority(url), meta.location),
+            path: meta.location,
+        })
+        .collect())
+}
+
+fn url_authority(url: &Url) -> String {
+    format!(
+        "{}{}",
+        url.host_str().unwrap_or_default(),
+        url.port().map(|p| format!(":{p}")).unwrap_or_default()
+    )
+}
+
+fn has_image_extension(path: &Path, extensions: &[String]) -> bool {
+    has_extension(
+        path.extension().and_then(|ext| ext.to_str()),
+        extensions,
+    )
+}
+
+fn has_extension(ext: Option<&str>, extensions: &[String]) -> bool {
+    ext.map(|ext| extensions.iter().any(|allowed| allowed.eq_ignore_ascii_case(ext)))
+        .unwrap_or(false)
+}
+
+/// Where processed rows are written: a local CSV file, or a CSV object kept
+/// in a bucket. Buckets have no append operation, so rows are buffered and
+/// the whole object is re-uploaded every `OBJECT_STORE_FLUSH_EVERY` rows
+/// (and via an explicit `flush` at the end of a run), rather than on every
+/// single completed image.
+pub enum ResultSink {
+    Local(Writer<std::fs::File>),
+    Object {
+        store: Arc<dyn ObjectStore>,
+        path: ObjectPath,
+        writer: Writer<Vec<u8>>,
+        unflushed_rows: usize,
+    },
+}
+
+impl ResultSink {
+    pub async fn write_record(&mut self, record: &[String]) -> Result<()> {
+        match self {
+            ResultSink::Local(writer) => {
+                writer.write_record(record)?;
+                writer.flush()?;
+                Ok(())
+            }
+            ResultSink::Object {
+                store,
+                path,
+                writer,
+                unflushed_rows,
+            } => {
+                writer.write_record(record)?;
+                writer.flush()?;
+                *unflushed_rows += 1;
+                if *unflushed_rows >= OBJECT_STORE_FLUSH_EVERY {
+                    upload(store, path, writer).await?;
+                    *unflushed_rows = 0;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Uploads any rows buffered since the last upload. A local sink is
+    /// already durable after every `write_record`, so this is a no-op for
+    /// it; callers should still call this once at the end of a run so a
+    /// bucket sink's tail isn't left unflushed.
+    pub async fn flush(&mut self) -> Result<()> {
+        if let ResultSink::Object {
+            store,
+            path,
+            writer,
+            unflushed_rows,
+        } = self
+        {
+            if *unflushed_rows > 0 {
+                upload(store, path, writer).await?;
+                *unflushed_rows = 0;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn upload(store: &Arc<dyn ObjectStore>, path: &ObjectPath, writer: &Writer<Vec<u8>>) -> Result<()> {
+    let bytes = writer.get_ref().clone();
+    store
+        .put(path, bytes.into())
+        .await
+        .context("Failed to upload CSV to object store")?;
+    Ok(())
+}
+
+/// Opens (creating if needed) the result sink named by `output` — a local
+/// path or an `s3://bucket/key` URI — and returns it alongside the
+/// already-processed paths from its first column. Fetches the existing CSV
+/// exactly once and reuses it for both.
+pub async fn open_output(output: &str) -> Result<(HashSet<String>, ResultSink)> {
+    let (existing_bytes, sink_backend) = match Url::parse(output) {
+        Ok(url) if is_bucket_url(&url) => {
+            let (store, path) = parse_url(&url).context("Failed to parse object store URL")?;
+            let store: Arc<dyn ObjectStore> = Arc::from(store);
+
+            let existing = match store.get(&path).await {
+                Ok(object) => object
+                    .bytes()
+                    .await
+                    .context("Failed to read existing CSV object")?
+                    .to_vec(),
+                Err(object_store::Error::NotFound { .. }) => Vec::new(),
+                Err(e) => return Err(e).context("Failed to check for existing CSV object"),
+            };
+
+            (existing, SinkBackend::Object { store, path })
+        }
+        _ => {
+            let path = Path::new(output);
+            let existing = if path.exists() {
+                std::fs::read(path).context("Failed to read existing CSV file")?
+            } else {
+                Vec::new()
+            };
+
+            (existing, SinkBackend::Local(path.to_owned()))
+        }
+    };
+
+    let processed = parse_processed_paths(&existing_bytes)?;
+
+    let sink = match sink_backend {
+        SinkBackend::Local(path) => ResultSink::Local(create_local_csv_writer(&path)?),
+        SinkBackend::Object { store, path } => ResultSink::Object {
+            store,
+            path,
+            writer: csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(existing_bytes),
+            unflushed_rows: 0,
+        },
+    };
+
+    Ok((processed, sink))
+}
+
+enum SinkBackend {
+    Local(PathBuf),
+    Object {
+        store: Arc<dyn ObjectStore>,
+        path: ObjectPath,
+    },
+}
+
+fn create_local_csv_writer(path: &Path) -> Result<Writer<std::fs::File>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context("Failed to open or create CSV file")?;
+
+    Ok(csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(file))
+}
+
+/// Parses the first column of each row in an in-memory CSV into a set of
+/// already-processed identifiers.
+fn parse_processed_paths(bytes: &[u8]) -> Result<HashSet<String>> {
+    let mut processed = HashSet::new();
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(bytes);
+
+    for record in reader.records() {
+        let record = record.context("Failed to read existing CSV record")?;
+        if let Some(path_str) = record.get(0) {
+            processed.insert(path_str.to_owned());
+        }
+    }
+
+    Ok(processed)
+}